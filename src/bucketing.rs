@@ -0,0 +1,96 @@
+/// Maps `[0, 2^64)` onto `[0, 1)`.
+const TWO_POW_64: f64 = 18_446_744_073_709_551_616.0;
+
+/// Deterministically maps a client to a bucket using a stable hash fraction
+/// instead of seeding an RNG and sampling a `WeightedIndex`. Each client's
+/// fraction never changes, so appending a bucket only pulls users into it
+/// from the tail of the range, instead of reshuffling the whole population
+/// the way re-sampling on every config change does.
+///
+/// Weights are interpreted as relative shares of the unit interval: bucket
+/// `i` is allotted `weight[i] / sum(weights)` of `[0, 1)`, in declared order.
+pub fn assign_bucket<'a>(
+    cid: &str,
+    test_name: &str,
+    buckets: &'a [String],
+    weights: &[i32],
+) -> &'a str {
+    pick_bucket(hash_fraction(cid, test_name), buckets, weights)
+}
+
+/// Computes the client's fixed position in `[0, 1)` for a given test. The
+/// conversion to `f64` rounds the very largest hashes up to `2^64`, so for
+/// those `f` comes out as exactly `1.0`.
+fn hash_fraction(cid: &str, test_name: &str) -> f64 {
+    let digest: [u8; 16] = md5::compute(format!("{}:{}", cid, test_name)).into();
+    let h = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+    h as f64 / TWO_POW_64
+}
+
+/// Picks the first bucket whose cumulative weight boundary exceeds `f`.
+fn pick_bucket<'a>(f: f64, buckets: &'a [String], weights: &[i32]) -> &'a str {
+    let total: i64 = weights.iter().map(|&w| w as i64).sum();
+    let mut cumulative = 0i64;
+    for (bucket, weight) in buckets.iter().zip(weights) {
+        cumulative += *weight as i64;
+        if f < cumulative as f64 / total as f64 {
+            return bucket;
+        }
+    }
+    // The last boundary is exactly 1.0, which an `f` rounded up to 1.0 by
+    // `hash_fraction` does not fall below; the final bucket is the correct
+    // fallback.
+    buckets.last().expect("buckets must be non-empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn picks_bucket_by_cumulative_boundary() {
+        let buckets = strings(&["small", "medium", "large"]);
+        let weights = vec![7, 3, 2]; // boundaries: 7/12, 10/12, 12/12
+
+        assert_eq!(pick_bucket(0.0, &buckets, &weights), "small");
+        assert_eq!(pick_bucket(0.5, &buckets, &weights), "small");
+        assert_eq!(pick_bucket(0.6, &buckets, &weights), "medium");
+        assert_eq!(pick_bucket(0.9, &buckets, &weights), "large");
+    }
+
+    #[test]
+    fn assign_bucket_is_deterministic() {
+        let buckets = strings(&["A", "B"]);
+        let weights = vec![1, 1];
+
+        let first = assign_bucket("client-123", "enrich", &buckets, &weights);
+        let second = assign_bucket("client-123", "enrich", &buckets, &weights);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn appending_a_bucket_only_pulls_from_the_tail() {
+        let buckets_before = strings(&["A", "B"]);
+        let weights_before = vec![1, 1]; // boundaries: 0.5, 1.0
+
+        let buckets_after = strings(&["A", "B", "C"]);
+        let weights_after = vec![1, 1, 1]; // boundaries: 0.333.., 0.667.., 1.0
+
+        // A client whose fraction falls below the *shrunk* boundary for "A"
+        // stays in "A" whether or not the new bucket exists, since that
+        // boundary can only move down (never up) when weight is added.
+        let f = 0.1;
+        assert_eq!(pick_bucket(f, &buckets_before, &weights_before), "A");
+        assert_eq!(pick_bucket(f, &buckets_after, &weights_after), "A");
+
+        // A client pushed into the new tail range moves into the new bucket,
+        // not into an unrelated earlier one.
+        let f = 0.9;
+        assert_eq!(pick_bucket(f, &buckets_before, &weights_before), "B");
+        assert_eq!(pick_bucket(f, &buckets_after, &weights_after), "C");
+    }
+}
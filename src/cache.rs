@@ -0,0 +1,127 @@
+use fastly::cache::simple::{get_or_set_with, CacheEntry};
+use fastly::http::header::CONTENT_TYPE;
+use fastly::http::StatusCode;
+use fastly::{Dictionary, Error, Response};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_TTL_SECS: u64 = 15 * 60;
+
+/// Builds a deterministic cache key from the sorted set of `test_name ->
+/// bucket` assignments a client was actually given, plus the request path.
+/// Requests that share a bucket combination produce byte-identical output,
+/// so they can share one cached entry instead of each hitting the origin.
+///
+/// This is built from the buckets `main` assigned, not by re-reading
+/// `Fastly-ABTest-*` request headers: those still reflect whatever the
+/// client sent for any test name outside the dictionary's `tests` list, so a
+/// client could otherwise force a unique cache key per request.
+pub fn variant_key(path: &str, assigned_buckets: &HashMap<String, String>) -> String {
+    let mut variant: Vec<String> = assigned_buckets
+        .iter()
+        .map(|(test_name, bucket)| format!("{}={}", test_name, bucket))
+        .collect();
+    variant.sort();
+    format!("{}?{}", path, variant.join("&"))
+}
+
+/// Reads the cache TTL from the `ab_config` dictionary's `cache_ttl` entry
+/// (in seconds), defaulting to 15 minutes.
+pub fn ttl(dict: &Dictionary) -> Duration {
+    let secs = dict
+        .get("cache_ttl")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TTL_SECS);
+    Duration::from_secs(secs)
+}
+
+/// The status, content type, and body of a fully-enriched origin response,
+/// as stored in the edge cache. Per-user data such as the `ab_cid`
+/// Set-Cookie header is layered on after the cache lookup, never cached
+/// itself.
+struct CachedVariant {
+    status: u16,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+impl CachedVariant {
+    fn from_response(resp: Response) -> Self {
+        let status = resp.get_status().as_u16();
+        let content_type = resp.get_content_type().map(|mime| mime.to_string());
+        let body = resp.into_body_bytes();
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn is_success(&self) -> bool {
+        StatusCode::from_u16(self.status)
+            .map(|status| status.is_success())
+            .unwrap_or(false)
+    }
+
+    fn into_response(self) -> Response {
+        let mut resp = Response::from_body(self.body);
+        resp.set_status(StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK));
+        if let Some(content_type) = self.content_type {
+            resp.set_header(CONTENT_TYPE, content_type);
+        }
+        resp
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let content_type = self.content_type.as_deref().unwrap_or_default();
+        let mut bytes = self.status.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(content_type.as_bytes());
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    fn decode(bytes: Vec<u8>) -> Self {
+        let status = u16::from_be_bytes(bytes[..2].try_into().expect("2 byte status"));
+        let len =
+            u32::from_be_bytes(bytes[2..6].try_into().expect("4 byte length prefix")) as usize;
+        let content_type = String::from_utf8(bytes[6..6 + len].to_vec())
+            .ok()
+            .filter(|s| !s.is_empty());
+        let body = bytes[6 + len..].to_vec();
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+}
+
+/// Serves the cached variant for `key` if present; otherwise runs `fetch`
+/// and caches the response it returns. `fetch` should already
+/// have run the enrich rewrite, so the cached entry is fully transformed.
+///
+/// Only successful responses are kept around for `ttl`; anything else (an
+/// origin error, a redirect, ...) is still returned to this caller but
+/// expires immediately, so it isn't replayed as a cached 200 to every other
+/// client in the same variant bucket.
+pub fn get_or_fetch<F>(key: String, ttl: Duration, fetch: F) -> Result<Response, Error>
+where
+    F: FnOnce() -> Result<Response, Error>,
+{
+    let body = get_or_set_with(key, || {
+        let variant = CachedVariant::from_response(fetch()?);
+        let entry_ttl = if variant.is_success() {
+            ttl
+        } else {
+            Duration::ZERO
+        };
+        Ok(CacheEntry {
+            value: variant.encode().into(),
+            ttl: entry_ttl,
+        })
+    })?
+    .expect("get_or_set_with always produces a body for the key it was given");
+
+    Ok(CachedVariant::decode(body.into_bytes()).into_response())
+}
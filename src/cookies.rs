@@ -0,0 +1,83 @@
+use cookie::time::Duration;
+use cookie::{Cookie, CookieJar, SameSite};
+use fastly::Dictionary;
+
+use crate::CID_COOKIE;
+
+/// Operator-configurable attributes for the `ab_cid` cookie, read from the
+/// `ab_config` dictionary so deployments that embed this demo cross-site can
+/// switch to `SameSite=None`.
+pub struct CookieSettings {
+    same_site: SameSite,
+    secure: bool,
+    http_only: bool,
+    max_age: Duration,
+}
+
+impl CookieSettings {
+    pub fn from_dict(dict: &Dictionary) -> Self {
+        let same_site = match dict.get("cookie_samesite").as_deref() {
+            Some("None") => SameSite::None,
+            Some("Strict") => SameSite::Strict,
+            _ => SameSite::Lax,
+        };
+        let secure = dict
+            .get("cookie_secure")
+            .map(|v| v != "false")
+            .unwrap_or(true);
+        let http_only = dict
+            .get("cookie_httponly")
+            .map(|v| v != "false")
+            .unwrap_or(true);
+        let max_age = dict
+            .get("cookie_max_age")
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(31_536_000);
+
+        Self {
+            same_site,
+            secure,
+            http_only,
+            max_age: Duration::seconds(max_age),
+        }
+    }
+}
+
+/// Parses the incoming `Cookie` header into a jar and pulls the `ab_cid`
+/// value out of it, leaving the jar holding only the cookies that should be
+/// forwarded to the origin.
+pub fn extract_cid(header: &str) -> (CookieJar, Option<String>) {
+    let mut jar = CookieJar::new();
+    for cookie in Cookie::split_parse_encoded(header.to_owned()).flatten() {
+        jar.add_original(cookie.into_owned());
+    }
+
+    let cid = jar.get(CID_COOKIE).map(|c| c.value().to_owned());
+    if cid.is_some() {
+        jar.remove(Cookie::from(CID_COOKIE));
+    }
+    (jar, cid)
+}
+
+/// Re-serializes whatever cookies remain in the jar for the origin request's
+/// `Cookie` header. Uses the jar's percent-encoding serialization so a name
+/// or value containing `;`, `,`, `"`, or other separator characters comes
+/// back out encoded, the same way it went in via `split_parse_encoded`.
+pub fn stringify(jar: &CookieJar) -> String {
+    jar.iter()
+        .map(|c| c.encoded().to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Builds the `Set-Cookie` header value for a freshly assigned `ab_cid`.
+pub fn build_setcookie(id: &str, settings: &CookieSettings) -> String {
+    Cookie::build((CID_COOKIE, id.to_owned()))
+        .path("/")
+        .max_age(settings.max_age)
+        .same_site(settings.same_site)
+        .secure(settings.secure)
+        .http_only(settings.http_only)
+        .build()
+        .to_string()
+}
@@ -1,50 +1,167 @@
 use fastly::Response;
-use lol_html::html_content::ContentType;
-use lol_html::{element, rewrite_str, text, RewriteStrSettings};
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use serde::Deserialize;
 
-// Load files into the constants
-const FONT_LINKS: &str = include_str!("font.html");
-const STYLE: &str = include_str!("style.css");
+/// A single DOM mutation to apply when a client lands in the bucket that
+/// carries it. Rules are authored as JSON inside the `ab_config` dictionary
+/// alongside the bucket/weight definition, so new experiments can mutate the
+/// page without touching this binary.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TransformAction {
+    SetInnerContent {
+        content: String,
+        #[serde(default)]
+        html: bool,
+    },
+    After {
+        content: String,
+        #[serde(default)]
+        html: bool,
+    },
+    SetAttribute {
+        name: String,
+        value: String,
+    },
+    Remove,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransformRule {
+    pub selector: String,
+    #[serde(flatten)]
+    pub action: TransformAction,
+}
+
+fn apply_action(
+    e: &mut Element,
+    action: &TransformAction,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match action {
+        TransformAction::SetInnerContent { content, html } => {
+            e.set_inner_content(content, content_type(*html));
+        }
+        TransformAction::After { content, html } => {
+            e.after(content, content_type(*html));
+        }
+        TransformAction::SetAttribute { name, value } => {
+            e.set_attribute(name, value)?;
+        }
+        TransformAction::Remove => {
+            e.remove();
+        }
+    }
+    Ok(())
+}
+
+fn content_type(html: bool) -> ContentType {
+    if html {
+        ContentType::Html
+    } else {
+        ContentType::Text
+    }
+}
+
+// Leading byte patterns that mark a body as HTML: a subset of the prefix list
+// browsers use when mime-sniffing an ambiguous or missing Content-Type
+// (https://mimesniff.spec.whatwg.org/#matching-an-html-byte-pattern),
+// matched here as plain case-insensitive prefixes.
+const HTML_SIGNATURES: &[&str] = &["<!doctype html", "<html", "<head", "<body", "<!--"];
+
+enum ContentTypeHint {
+    /// Content-Type says text/html; trust it without sniffing the body.
+    Html,
+    /// No Content-Type, or one of the handful of values real servers use as
+    /// a stand-in for "I didn't bother setting this" (text/plain,
+    /// application/octet-stream, */*) — sniff the body to decide.
+    Ambiguous,
+    /// Content-Type names something else entirely (image/*, application/json,
+    /// etc). Never rewrite these.
+    Other,
+}
+
+fn content_type_hint(resp: &Response) -> ContentTypeHint {
+    match resp.get_content_type() {
+        None => ContentTypeHint::Ambiguous,
+        Some(mime) if mime.type_() == mime::TEXT && mime.subtype() == mime::HTML => {
+            ContentTypeHint::Html
+        }
+        Some(mime)
+            if (mime.type_() == mime::TEXT && mime.subtype() == mime::PLAIN)
+                || (mime.type_() == mime::APPLICATION && mime.subtype() == "octet-stream")
+                || mime.type_() == mime::STAR =>
+        {
+            ContentTypeHint::Ambiguous
+        }
+        Some(_) => ContentTypeHint::Other,
+    }
+}
+
+fn sniffs_as_html(body: &[u8]) -> bool {
+    let trimmed = body
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map_or(&body[..0], |start| &body[start..]);
+    let prefix = String::from_utf8_lossy(&trimmed[..trimmed.len().min(15)]).to_lowercase();
+    HTML_SIGNATURES.iter().any(|sig| prefix.starts_with(sig))
+}
+
+/// Runs every rule contributed by every bucket a client was assigned to
+/// through a single lol_html pass, so multiple concurrent experiments can
+/// each mutate the DOM without conflicting with one another.
+///
+/// Only runs the rewriter when the response is confidently `text/html`;
+/// otherwise (or if the rewrite itself fails) the response is passed through
+/// untouched instead of corrupting the body or panicking.
+pub fn rewrite_if_html(beresp: Response, rules: &[TransformRule]) -> Response {
+    let confidently_html = match content_type_hint(&beresp) {
+        ContentTypeHint::Other => return beresp,
+        ContentTypeHint::Html => true,
+        ContentTypeHint::Ambiguous => false,
+    };
 
-pub fn rewrite_html(beresp: Response) -> Response {
     let resp = beresp.clone_without_body();
-    let element_content_handlers = vec![
-        // Insert Google Fonts link tags
-        element!("meta[name]", |e| {
-            e.after(FONT_LINKS, ContentType::Html);
-            Ok(())
-        }),
-        // Replace CSS in the style tags
-        element!("style", |e| {
-            e.set_inner_content(STYLE, ContentType::Text);
-            Ok(())
-        }),
-        // Modify inner contents of h1 tags - enclose each word with span tags.
-        // "<h1>Example Domain</h1>" -> "<h1><span>Example</span><span>Domain</span></h1>"
-        text!("h1", |t| {
-            if !t.last_in_text_node() {
-                let tagged_t = t
-                    .as_str()
-                    .split(" ")
-                    .map(|w| format!("<span>{}</span>", w))
-                    .fold(String::new(), |mut acc, cur| {
-                        acc.push_str(cur.as_str());
-                        acc
-                    });
-                t.replace(&tagged_t, ContentType::Html);
-            }
-            Ok(())
-        }),
-    ];
-
-    let html = rewrite_str(
-        &beresp.into_body_str(),
+    let body = beresp.into_body_bytes();
+
+    // Sniff on the raw bytes: a lossy UTF-8 conversion done up front would
+    // already have mangled a genuinely binary body before we ever decide
+    // whether to rewrite it.
+    if !confidently_html && !sniffs_as_html(&body) {
+        return resp.with_body(body);
+    }
+
+    // Only now, with the body confirmed to be HTML, is a UTF-8 conversion
+    // appropriate.
+    let body = match String::from_utf8(body) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("html body was not valid UTF-8, passing response through untouched");
+            return resp.with_body(e.into_bytes());
+        }
+    };
+
+    let element_content_handlers = rules
+        .iter()
+        .map(|rule| {
+            element!(rule.selector.clone(), move |e| apply_action(
+                e,
+                &rule.action
+            ))
+        })
+        .collect();
+
+    match rewrite_str(
+        &body,
         RewriteStrSettings {
             element_content_handlers,
             ..RewriteStrSettings::default()
         },
-    )
-    .unwrap();
-
-    resp.with_body(html)
+    ) {
+        Ok(html) => resp.with_body(html),
+        Err(e) => {
+            eprintln!("html rewrite failed, passing response through untouched: {}", e);
+            resp.with_body(body)
+        }
+    }
 }
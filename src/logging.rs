@@ -0,0 +1,57 @@
+use fastly::log::Endpoint;
+use fastly::Dictionary;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One structured exposure event describing which bucket a client landed in
+/// for each test on this request, so operators can compute conversion and
+/// bucket-distribution metrics downstream without touching origin behavior.
+#[derive(Serialize)]
+struct Exposure<'a> {
+    client_id: &'a str,
+    is_new: bool,
+    timestamp: u64,
+    path: &'a str,
+    buckets: &'a HashMap<String, String>,
+}
+
+/// Emits one JSON exposure event to the log endpoint named by the
+/// `ab_config` dictionary's `log_endpoint` entry, skipping gracefully when
+/// it isn't configured.
+pub fn log_exposure(
+    dict: &Dictionary,
+    client_id: &str,
+    is_new: bool,
+    path: &str,
+    buckets: &HashMap<String, String>,
+) {
+    let Some(endpoint_name) = dict.get("log_endpoint") else {
+        return;
+    };
+
+    let event = Exposure {
+        client_id,
+        is_new,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        path,
+        buckets,
+    };
+
+    let body = match serde_json::to_string(&event) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("failed to serialize exposure event: {}", e);
+            return;
+        }
+    };
+
+    let mut endpoint = Endpoint::from_name(&endpoint_name);
+    if let Err(e) = writeln!(endpoint, "{}", body) {
+        eprintln!("failed to write exposure event to {}: {}", endpoint_name, e);
+    }
+}
@@ -14,17 +14,21 @@
 //        "weight": "1:1"
 //    }
 //}
+mod bucketing;
+mod cache;
+mod cookies;
 mod enrich;
+mod logging;
 
+use cookies::CookieSettings;
 use fastly::http::header::{ACCEPT_ENCODING, CACHE_CONTROL, HOST, SET_COOKIE};
 use fastly::{Dictionary, Error, Request, Response};
-use rand::distributions::WeightedIndex;
-use rand::prelude::*;
-use rand::rngs::StdRng;
 use serde::{de, Deserialize, Deserializer};
 use std::collections::HashMap;
 use uuid::Uuid;
 
+use enrich::TransformRule;
+
 const BACKEND_NAME: &str = "origin_0";
 const DICT_NAME: &str = "ab_config";
 const CID_COOKIE: &str = "ab_cid";
@@ -32,8 +36,14 @@ const CID_COOKIE: &str = "ab_cid";
 #[derive(Debug, Deserialize)]
 struct ABTest {
     buckets: Vec<String>,
+    // Relative shares of the unit interval a client's hash fraction is
+    // mapped onto; see bucketing::assign_bucket.
     #[serde(deserialize_with = "weight_deserializer")]
     weight: Vec<i32>,
+    // Per-bucket lol_html rules to run when a client lands in that bucket,
+    // keyed by bucket name. Absent for tests that don't touch the DOM.
+    #[serde(default)]
+    transforms: HashMap<String, Vec<TransformRule>>,
 }
 
 // Custom deserializer to parse a weight ratio expression like "7:3:2" into Vec<i32>
@@ -70,51 +80,11 @@ impl ClientID {
     fn from_id(id: String) -> Self {
         Self { id, is_new: false }
     }
-    fn as_setcookie(&self) -> String {
-        format!(
-            // "{}={}; max-age=31536000; path=/; httponly",
-            "{}={}; Max-Age=31536000; Path=/; Secure; HttpOnly",
-            CID_COOKIE,
-            self.id
-        )
+    fn as_setcookie(&self, settings: &CookieSettings) -> String {
+        cookies::build_setcookie(&self.id, settings)
     }
 }
 
-fn load_cookie(cookie: &str) -> HashMap<String, String> {
-    cookie
-        .split(";")
-        .filter_map(|kv| {
-            kv.find("=").map(|index| {
-                let (key, value) = kv.split_at(index);
-                let key = key.trim().to_string();
-                let value = value[1..].to_string();
-                (key, value)
-            })
-        })
-        .collect()
-}
-
-fn stringify_cookie(cookie_jar: HashMap<String, String>) -> String {
-    cookie_jar
-        .iter()
-        .map(|(k, v)| format!("{}={}", k, v))
-        .collect::<Vec<_>>()
-        .join("; ")
-}
-
-fn create_rng(cid: &str, test_name: &str) -> StdRng {
-    // Mapping a user to the same set of A/B test buckets
-    // by generating a seed from a client ID and a test name.
-    let digest1: [u8; 16] = md5::compute(cid).into();
-    let digest2: [u8; 16] = md5::compute(test_name).into();
-
-    let mut seed: [u8; 32] = Default::default();
-    seed[..16].copy_from_slice(&digest1);
-    seed[16..].copy_from_slice(&digest2);
-
-    rand::SeedableRng::from_seed(seed)
-}
-
 #[fastly::main]
 fn main(mut req: Request) -> Result<Response, Error> {
     let abtest_config = Dictionary::open(DICT_NAME);
@@ -126,30 +96,36 @@ fn main(mut req: Request) -> Result<Response, Error> {
         // Allocate a client ID if they don't already have one.
         let cid = match req.get_header("cookie") {
             Some(cookie) => {
-                let mut cookie_jar = load_cookie(cookie.to_str()?);
-                match cookie_jar.remove(CID_COOKIE) {
-                    Some(id) => {
-                        req.set_header("cookie", stringify_cookie(cookie_jar));
-                        ClientID::from_id(id)
-                    }
+                let (jar, cid) = cookies::extract_cid(cookie.to_str()?);
+                req.set_header("cookie", cookies::stringify(&jar));
+                match cid {
+                    Some(id) => ClientID::from_id(id),
                     None => ClientID::new(),
                 }
             }
             None => ClientID::new(),
         };
 
-        // Assign them a bucket for each test and add Fastly-ABTest-X headers to the origin request.
+        // Assign them a bucket for each test, add Fastly-ABTest-X headers to the
+        // origin request, and collect any DOM transform rules the assigned
+        // buckets carry.
+        let mut transform_rules: Vec<TransformRule> = Vec::new();
+        let mut assigned_buckets: HashMap<String, String> = HashMap::new();
         for test_name in tests {
             match abtest_config.get(&test_name) {
                 Some(v) => {
                     let abtest = serde_json::from_str::<ABTest>(&v).unwrap();
-                    let mut rng = create_rng(&cid.id, &test_name);
 
-                    // Pick a bucket according to the weight.
-                    let dist = WeightedIndex::new(&abtest.weight).unwrap();
-                    let bucket = &abtest.buckets[dist.sample(&mut rng)];
+                    // Map the client to a bucket via a stable hash fraction.
+                    let bucket =
+                        bucketing::assign_bucket(&cid.id, &test_name, &abtest.buckets, &abtest.weight);
+
+                    if let Some(rules) = abtest.transforms.get(bucket) {
+                        transform_rules.extend(rules.iter().cloned());
+                    }
 
                     req.set_header(format!("Fastly-ABTest-{}", test_name), bucket);
+                    assigned_buckets.insert(test_name, bucket.to_string());
                 }
                 None => {
                     eprintln!(
@@ -161,30 +137,41 @@ fn main(mut req: Request) -> Result<Response, Error> {
             }
         }
 
+        logging::log_exposure(
+            &abtest_config,
+            &cid.id,
+            cid.is_new,
+            req.get_path(),
+            &assigned_buckets,
+        );
+
+        // Every request in the same variant combination produces identical
+        // output, so fetch (and enrich) it once per combination and share
+        // the result across clients instead of hitting the origin for each.
+        let cache_key = cache::variant_key(req.get_path(), &assigned_buckets);
+        let cache_ttl = cache::ttl(&abtest_config);
+
         // Add the host header so that we don't need to specify it in a request when testing locally
         req.set_header(HOST, "example.com");
         // Request an uncompressed response
         req.remove_header(ACCEPT_ENCODING);
-        let mut beresp = req.send(BACKEND_NAME)?;
+
+        let mut resp = cache::get_or_fetch(cache_key, cache_ttl, move || {
+            let beresp = req.send(BACKEND_NAME)?;
+            Ok(if transform_rules.is_empty() {
+                beresp
+            } else {
+                enrich::rewrite_if_html(beresp, &transform_rules)
+            })
+        })?;
 
         if cid.is_new {
-            beresp.set_header(SET_COOKIE, cid.as_setcookie());
-            beresp.set_header(CACHE_CONTROL, "no-store");
+            let cookie_settings = CookieSettings::from_dict(&abtest_config);
+            resp.set_header(SET_COOKIE, cid.as_setcookie(&cookie_settings));
+            resp.set_header(CACHE_CONTROL, "no-store");
         }
 
-        // If the client is assigned bucket B in the "enrich" test, rewrite the HTML
-        match beresp
-            .get_backend_request()
-            .unwrap()
-            .get_header("Fastly-ABTest-enrich")
-        {
-            Some(bucket) if bucket == "B" => {
-                return Ok(enrich::rewrite_html(beresp));
-            }
-            _ => {
-                return Ok(beresp);
-            }
-        }
+        return Ok(resp);
     }
 
     Ok(req.send(BACKEND_NAME)?)